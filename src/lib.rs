@@ -49,7 +49,8 @@ impl std::fmt::Display for Version {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MigrationInfo {
     pub version: Version,
-    pub name: String
+    pub name: String,
+    pub checksum: Vec<u8>
 }
 
 #[derive(Debug, Clone)]
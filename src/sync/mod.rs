@@ -1,3 +1,4 @@
+pub mod postgres;
 pub mod sqlite;
 
 use std::collections::HashMap;
@@ -18,17 +19,24 @@ pub trait Transaction {
     type Err;
 
     fn save_migration(&self, info: &ExecutedMigrationInfo) -> Result<(), Self::Err>;
+    fn delete_migration(&self, version: &Version) -> Result<(), Self::Err>;
 }
 
+pub type Migration<C> = Box<dyn Fn(&<C as Connection>::Trans) -> Result<(), <C as Connection>::Err>>;
+
 pub trait Migrations {
     type C: Connection;
 
     fn all_migrations(&self) -> Vec<MigrationInfo>;
+    fn migration(&self, version: &Version) -> Option<&Migration<Self::C>>;
+    fn down_migration(&self, version: &Version) -> Option<&Migration<Self::C>>;
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum MigrationError<E> {
     UnexpectedMigrations(Vec<MigrationInfo>),
+    ChecksumMismatch { version: Version, expected: Vec<u8>, actual: Vec<u8> },
+    MissingDownMigration(Version),
     DatabaseError(E)
 }
 
@@ -40,7 +48,34 @@ impl <E> From<E> for MigrationError<E> {
 
 type MigrationState = Vec<(Option<MigrationInfo>, Option<ExecutedMigrationInfo>)>;
 
+#[derive(Debug, Clone, Default)]
+pub struct MigrateOptions {
+    pub target: Option<Version>,
+    pub ignore_missing: bool
+}
+
 pub fn migrate<C, T, E, M>(conn: &C, migrations: &M) -> Result<(), MigrationError<E>>
+    where T: Transaction<Err = E>,
+          C: Connection<Trans = T, Err = E>,
+          M: Migrations<C = C> {
+    run_migrate(conn, migrations, &MigrateOptions::default())
+}
+
+pub fn migrate_to<C, T, E, M>(conn: &C, migrations: &M, target: Version) -> Result<(), MigrationError<E>>
+    where T: Transaction<Err = E>,
+          C: Connection<Trans = T, Err = E>,
+          M: Migrations<C = C> {
+    run_migrate(conn, migrations, &MigrateOptions { target: Some(target), ..Default::default() })
+}
+
+pub fn migrate_with_options<C, T, E, M>(conn: &C, migrations: &M, options: MigrateOptions) -> Result<(), MigrationError<E>>
+    where T: Transaction<Err = E>,
+          C: Connection<Trans = T, Err = E>,
+          M: Migrations<C = C> {
+    run_migrate(conn, migrations, &options)
+}
+
+fn run_migrate<C, T, E, M>(conn: &C, migrations: &M, options: &MigrateOptions) -> Result<(), MigrationError<E>>
     where T: Transaction<Err = E>,
           C: Connection<Trans = T, Err = E>,
           M: Migrations<C = C> {
@@ -52,10 +87,84 @@ pub fn migrate<C, T, E, M>(conn: &C, migrations: &M) -> Result<(), MigrationErro
         .map(|m| (m.migration.version.clone(), m))
         .collect();
 
-    let migration_state = merge(available, existing).into_iter()
+    let mut next_sequence = existing.values()
+        .map(|m| m.sequence)
+        .max()
+        .unwrap_or(0) + 1;
+
+    check_checksums(&available, &existing)?;
+
+    let migration_state: MigrationState = merge(available, existing).into_iter()
         .map(|(_, v)| v)
         .collect();
-    check_unexpected_migrations(&migration_state)?;
+    if !options.ignore_missing {
+        check_unexpected_migrations(&migration_state)?;
+    }
+
+    let mut pending = pending_migrations(migration_state);
+    if let Some(target) = &options.target {
+        pending.retain(|m| m.version <= *target);
+    }
+    pending.sort_unstable_by(|m1, m2| m1.version.cmp(&m2.version));
+
+    conn.in_transaction(|t| {
+        for info in &pending {
+            let run = migrations.migration(&info.version)
+                .expect("pending migration disappeared from Migrations between lookup and run");
+            run(t)?;
+            t.save_migration(&ExecutedMigrationInfo {
+                migration: info.clone(),
+                sequence: next_sequence
+            })?;
+            next_sequence += 1;
+        }
+        Ok(())
+    }).map_err(|(_, e)| MigrationError::DatabaseError(e))?;
+
+    Ok(())
+}
+
+pub fn rollback<C, T, E, M>(conn: &C, migrations: &M, count: u32) -> Result<(), MigrationError<E>>
+    where T: Transaction<Err = E>,
+          C: Connection<Trans = T, Err = E>,
+          M: Migrations<C = C> {
+    let mut existing = conn.load_existing_migrations()?;
+    existing.sort_unstable_by(|m1, m2| m2.sequence.cmp(&m1.sequence));
+    existing.truncate(count as usize);
+    run_rollback(conn, migrations, existing)
+}
+
+pub fn rollback_to<C, T, E, M>(conn: &C, migrations: &M, target: Version) -> Result<(), MigrationError<E>>
+    where T: Transaction<Err = E>,
+          C: Connection<Trans = T, Err = E>,
+          M: Migrations<C = C> {
+    let existing: Vec<ExecutedMigrationInfo> = conn.load_existing_migrations()?.into_iter()
+        .filter(|m| m.migration.version > target)
+        .collect();
+    run_rollback(conn, migrations, existing)
+}
+
+fn run_rollback<C, T, E, M>(conn: &C, migrations: &M, mut executed: Vec<ExecutedMigrationInfo>) -> Result<(), MigrationError<E>>
+    where T: Transaction<Err = E>,
+          C: Connection<Trans = T, Err = E>,
+          M: Migrations<C = C> {
+    executed.sort_unstable_by(|m1, m2| m2.migration.version.cmp(&m1.migration.version));
+
+    for info in &executed {
+        if migrations.down_migration(&info.migration.version).is_none() {
+            return Err(MigrationError::MissingDownMigration(info.migration.version.clone()));
+        }
+    }
+
+    conn.in_transaction(|t| {
+        for info in &executed {
+            let down = migrations.down_migration(&info.migration.version)
+                .expect("down migration disappeared from Migrations between validation and rollback");
+            down(t)?;
+            t.delete_migration(&info.migration.version)?;
+        }
+        Ok(())
+    }).map_err(|(_, e)| MigrationError::DatabaseError(e))?;
 
     Ok(())
 }
@@ -71,6 +180,21 @@ fn merge<K: Eq + Hash, V1, V2>(m1: HashMap<K, V1>, m2: HashMap<K, V2>) -> HashMa
     result
 }
 
+fn check_checksums<E>(available: &HashMap<Version, MigrationInfo>, existing: &HashMap<Version, ExecutedMigrationInfo>) -> Result<(), MigrationError<E>> {
+    for (version, info) in available {
+        if let Some(executed) = existing.get(version) {
+            if executed.migration.checksum != info.checksum {
+                return Err(MigrationError::ChecksumMismatch {
+                    version: version.clone(),
+                    expected: executed.migration.checksum.clone(),
+                    actual: info.checksum.clone()
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
 fn check_unexpected_migrations<E>(migration_state: &MigrationState) -> Result<(), MigrationError<E>> {
     let mut unexpected_migrations: Vec<MigrationInfo> = migration_state.iter()
         .filter(|(a, _)| a.is_none())
@@ -83,40 +207,91 @@ fn check_unexpected_migrations<E>(migration_state: &MigrationState) -> Result<()
     Ok(())
 }
 
-pub struct MigrationsBuilder<C, E> {
-    migrations: HashMap<Version, (MigrationInfo, Box<dyn FnOnce(C) -> Result<(), E>>)>
+fn pending_migrations(migration_state: MigrationState) -> Vec<MigrationInfo> {
+    migration_state.into_iter()
+        .filter_map(|(available, executed)| match (available, executed) {
+            (Some(available), None) => Some(available),
+            _ => None
+        })
+        .collect()
 }
 
-impl <C, E> MigrationsBuilder<C, E> {
-    pub fn new() -> MigrationsBuilder<C, E> {
+pub struct MigrationsBuilder<C: Connection> {
+    migrations: HashMap<Version, (MigrationInfo, Migration<C>, Option<Migration<C>>)>
+}
+
+impl <C: Connection> MigrationsBuilder<C> {
+    pub fn new() -> MigrationsBuilder<C> {
         MigrationsBuilder {
             migrations: HashMap::new()
         }
     }
 
-    pub fn add_migration<V, S, F>(&mut self, version: V, name: S, f: F) -> Result<(), ParseVersionError>
+    pub fn add_migration<V, S, B, F>(&mut self, version: V, name: S, body: B, up: F) -> Result<(), ParseVersionError>
+        where V: Into<String>,
+              S: Into<String>,
+              B: AsRef<[u8]>,
+              F: Fn(&C::Trans) -> Result<(), C::Err> + 'static {
+        self.insert_migration(version, name, body, Box::new(up), None)
+    }
+
+    pub fn add_migration_with_down<V, S, B, F, D>(&mut self, version: V, name: S, body: B, up: F, down: D) -> Result<(), ParseVersionError>
+        where V: Into<String>,
+              S: Into<String>,
+              B: AsRef<[u8]>,
+              F: Fn(&C::Trans) -> Result<(), C::Err> + 'static,
+              D: Fn(&C::Trans) -> Result<(), C::Err> + 'static {
+        self.insert_migration(version, name, body, Box::new(up), Some(Box::new(down)))
+    }
+
+    fn insert_migration<V, S, B>(&mut self, version: V, name: S, body: B, up: Migration<C>, down: Option<Migration<C>>) -> Result<(), ParseVersionError>
         where V: Into<String>,
               S: Into<String>,
-              F: FnOnce(C) -> Result<(), E> + 'static {
+              B: AsRef<[u8]> {
         let version: Version = version.into().parse()?;
         let migration = MigrationInfo {
             version: version.clone(),
-            name: name.into()
+            name: name.into(),
+            checksum: checksum(body.as_ref())
         };
-        self.migrations.insert(version, (migration, Box::new(f)));
+        self.migrations.insert(version, (migration, up, down));
         Ok(())
     }
 }
 
-impl <C, E> Migrations for MigrationsBuilder<C, E>
-    where C: Connection<Err = E> {
+// FNV-1a (64-bit): a fixed, publicly documented algorithm, unlike
+// `std::collections::hash_map::DefaultHasher`, whose output the standard
+// library explicitly does not guarantee to be stable across releases. This
+// checksum is persisted and compared across process (and toolchain) restarts,
+// so it must stay reproducible forever.
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+fn checksum(body: &[u8]) -> Vec<u8> {
+    let mut hash = FNV_OFFSET_BASIS;
+    for byte in body {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash.to_be_bytes().to_vec()
+}
+
+impl <C: Connection> Migrations for MigrationsBuilder<C> {
     type C = C;
 
     fn all_migrations(&self) -> Vec<MigrationInfo> {
         self.migrations.values()
-            .map(|(m, _)| m.clone())
+            .map(|(m, _, _)| m.clone())
             .collect()
     }
+
+    fn migration(&self, version: &Version) -> Option<&Migration<C>> {
+        self.migrations.get(version).map(|(_, up, _)| up)
+    }
+
+    fn down_migration(&self, version: &Version) -> Option<&Migration<C>> {
+        self.migrations.get(version).and_then(|(_, _, down)| down.as_ref())
+    }
 }
 
 #[cfg(test)]
@@ -138,7 +313,8 @@ mod tests {
               N: Into<String> {
         MigrationInfo {
             version: version.into().parse().unwrap(),
-            name: name.into()
+            name: name.into(),
+            checksum: Vec::new()
         }
     }
 
@@ -146,8 +322,8 @@ mod tests {
     fn non_existent_migrations() {
         let connection = rusqlite::Connection::open_in_memory().unwrap();
 
-        let mut migrations: MigrationsBuilder<rusqlite::Connection, rusqlite::Error> = MigrationsBuilder::new();
-        migrations.add_migration("1.0.0", "test_migration", |_| Ok(())).unwrap();
+        let mut migrations: MigrationsBuilder<rusqlite::Connection> = MigrationsBuilder::new();
+        migrations.add_migration("1.0.0", "test_migration", b"up 1.0.0", |_| Ok(())).unwrap();
         connection.ensure_migration_table().unwrap();
         connection.in_transaction(|t| {
             t.save_migration(&executed_migration(1, "0.0.1", "fake1"))?;
@@ -167,4 +343,164 @@ mod tests {
             o => panic!("Unexpected result {:?}", o)
         }
     }
+
+    #[test]
+    fn applies_pending_migrations_in_order() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        let applied = Rc::new(RefCell::new(Vec::new()));
+
+        let mut migrations: MigrationsBuilder<rusqlite::Connection> = MigrationsBuilder::new();
+        let applied_clone = applied.clone();
+        migrations.add_migration("2.0.0", "second", b"up 2.0.0", move |_| {
+            applied_clone.borrow_mut().push("2.0.0");
+            Ok(())
+        }).unwrap();
+        let applied_clone = applied.clone();
+        migrations.add_migration("1.0.0", "first", b"up 1.0.0", move |_| {
+            applied_clone.borrow_mut().push("1.0.0");
+            Ok(())
+        }).unwrap();
+
+        migrate(&connection, &migrations).unwrap();
+
+        assert_eq!(vec!("1.0.0", "2.0.0"), *applied.borrow());
+
+        let mut existing = connection.load_existing_migrations().unwrap();
+        existing.sort_unstable_by(|m1, m2| m1.sequence.cmp(&m2.sequence));
+        assert_eq!(vec!(
+                (1, "1.0.0".to_owned(), "first".to_owned()),
+                (2, "2.0.0".to_owned(), "second".to_owned())
+            ),
+            existing.iter()
+                .map(|m| (m.sequence, m.migration.version.to_string(), m.migration.name.clone()))
+                .collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn ignore_missing_tolerates_unknown_executed_migrations() {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+
+        let mut migrations: MigrationsBuilder<rusqlite::Connection> = MigrationsBuilder::new();
+        migrations.add_migration("1.0.0", "test_migration", b"up 1.0.0", |_| Ok(())).unwrap();
+        connection.ensure_migration_table().unwrap();
+        connection.in_transaction(|t| {
+            t.save_migration(&executed_migration(1, "0.0.1", "fake1"))
+        }).unwrap();
+
+        migrate_with_options(&connection, &migrations, MigrateOptions {
+            ignore_missing: true,
+            ..Default::default()
+        }).unwrap();
+
+        let existing = connection.load_existing_migrations().unwrap();
+        assert_eq!(2, existing.len());
+    }
+
+    #[test]
+    fn detects_checksum_mismatch() {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+
+        let mut migrations: MigrationsBuilder<rusqlite::Connection> = MigrationsBuilder::new();
+        migrations.add_migration("1.0.0", "first", b"up 1.0.0 edited", |_| Ok(())).unwrap();
+        connection.ensure_migration_table().unwrap();
+        connection.in_transaction(|t| {
+            t.save_migration(&ExecutedMigrationInfo {
+                sequence: 1,
+                migration: migration("1.0.0", "first")
+            })
+        }).unwrap();
+
+        let actual = migrate(&connection, &migrations);
+        match actual {
+            Err(MigrationError::ChecksumMismatch { version, .. }) => assert_eq!("1.0.0".parse::<Version>().unwrap(), version),
+            o => panic!("Unexpected result {:?}", o)
+        }
+    }
+
+    #[test]
+    fn migrates_only_up_to_target_version() {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+
+        let mut migrations: MigrationsBuilder<rusqlite::Connection> = MigrationsBuilder::new();
+        migrations.add_migration("1.0.0", "first", b"up 1.0.0", |_| Ok(())).unwrap();
+        migrations.add_migration("2.0.0", "second", b"up 2.0.0", |_| Ok(())).unwrap();
+        migrations.add_migration("3.0.0", "third", b"up 3.0.0", |_| Ok(())).unwrap();
+
+        migrate_to(&connection, &migrations, "2.0.0".parse().unwrap()).unwrap();
+
+        let mut existing = connection.load_existing_migrations().unwrap();
+        existing.sort_unstable_by(|m1, m2| m1.migration.version.cmp(&m2.migration.version));
+        assert_eq!(vec!(
+                ("1.0.0".to_owned(), "first".to_owned()),
+                ("2.0.0".to_owned(), "second".to_owned())
+            ),
+            existing.into_iter().map(|m| (m.migration.version.to_string(), m.migration.name)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn rolls_back_the_requested_count() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        let rolled_back = Rc::new(RefCell::new(Vec::new()));
+
+        let mut migrations: MigrationsBuilder<rusqlite::Connection> = MigrationsBuilder::new();
+        let rolled_back_clone = rolled_back.clone();
+        migrations.add_migration_with_down("1.0.0", "first", b"up 1.0.0", |_| Ok(()), move |_| {
+            rolled_back_clone.borrow_mut().push("1.0.0");
+            Ok(())
+        }).unwrap();
+        let rolled_back_clone = rolled_back.clone();
+        migrations.add_migration_with_down("2.0.0", "second", b"up 2.0.0", |_| Ok(()), move |_| {
+            rolled_back_clone.borrow_mut().push("2.0.0");
+            Ok(())
+        }).unwrap();
+
+        migrate(&connection, &migrations).unwrap();
+        rollback(&connection, &migrations, 1).unwrap();
+
+        assert_eq!(vec!("2.0.0"), *rolled_back.borrow());
+        assert_eq!(1, connection.load_existing_migrations().unwrap().len());
+    }
+
+    #[test]
+    fn migrations_survive_a_rollback_and_can_be_reapplied() {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+
+        let mut migrations: MigrationsBuilder<rusqlite::Connection> = MigrationsBuilder::new();
+        migrations.add_migration_with_down("1.0.0", "first", b"up 1.0.0", |_| Ok(()), |_| Ok(())).unwrap();
+
+        migrate(&connection, &migrations).unwrap();
+        rollback(&connection, &migrations, 1).unwrap();
+        migrate(&connection, &migrations).unwrap();
+
+        let existing = connection.load_existing_migrations().unwrap();
+        assert_eq!(1, existing.len());
+        assert_eq!("1.0.0", existing[0].migration.version.to_string());
+    }
+
+    #[test]
+    fn rollback_of_migration_without_down_fails_without_opening_a_transaction() {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+
+        let mut migrations: MigrationsBuilder<rusqlite::Connection> = MigrationsBuilder::new();
+        migrations.add_migration("1.0.0", "first", b"up 1.0.0", |_| Ok(())).unwrap();
+
+        migrate(&connection, &migrations).unwrap();
+        let actual = rollback(&connection, &migrations, 1);
+
+        match actual {
+            Err(MigrationError::MissingDownMigration(version)) => assert_eq!("1.0.0".parse::<Version>().unwrap(), version),
+            o => panic!("Unexpected result {:?}", o)
+        }
+
+        // the migration is still recorded, and BEGIN succeeds, which SQLite
+        // refuses inside an already open transaction
+        assert_eq!(1, connection.load_existing_migrations().unwrap().len());
+        assert!(connection.execute("begin", rusqlite::NO_PARAMS).is_ok());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,133 @@
+//! A `Connection`/`Transaction` backend on top of the `postgres` crate.
+//!
+//! This module has no integration tests against a real PostgreSQL server —
+//! there isn't one available in this crate's test environment. Only the
+//! pieces that don't require a live connection (currently, the
+//! `is_missing_migration_table` string match) are covered here.
+
+use std::cell::RefCell;
+use super::super::{ExecutedMigrationInfo, MigrationInfo, Version};
+
+pub struct Client {
+    inner: RefCell<postgres::Client>
+}
+
+impl Client {
+    pub fn new(inner: postgres::Client) -> Client {
+        Client {
+            inner: RefCell::new(inner)
+        }
+    }
+}
+
+impl super::Connection for Client {
+    type Err = postgres::Error;
+    type Trans = Self;
+
+    fn ensure_migration_table(&self) -> Result<(), Self::Err> {
+        let result = self.inner.borrow_mut().execute("SELECT 1 FROM migration LIMIT 0", &[]);
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                if is_missing_migration_table(&e) {
+                    self.inner.borrow_mut().batch_execute(r"
+                        CREATE TABLE migration (
+                            sequence integer not null primary key,
+                            version text not null unique,
+                            name text not null,
+                            checksum bytea not null,
+                            applied_at timestamptz not null default now()
+                        )
+                    ")
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    fn load_existing_migrations(&self) -> Result<Vec<ExecutedMigrationInfo>, Self::Err> {
+        self.inner.borrow_mut()
+            .query("SELECT sequence, version, name, checksum FROM migration", &[])?
+            .into_iter()
+            .map(|row| {
+                let version_str: String = row.get(1);
+                Ok(ExecutedMigrationInfo {
+                    sequence: row.get::<_, i32>(0) as u32,
+                    migration: MigrationInfo {
+                        version: version_str.parse().expect("invalid version stored in migration table"),
+                        name: row.get(2),
+                        checksum: row.get(3)
+                    }
+                })
+            })
+            .collect()
+    }
+
+    fn in_transaction<F>(&self, f: F) -> Result<(), (bool, Self::Err)>
+        where F: FnOnce(&Self::Trans) -> Result<(), Self::Err> {
+        self.inner.borrow_mut().batch_execute("BEGIN").map_err(|e| (false, e))?;
+
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(self))) {
+            Ok(Ok(())) => self.inner.borrow_mut().batch_execute("COMMIT").map_err(|e| (false, e)),
+            Ok(Err(e)) => {
+                let rolled_back = self.inner.borrow_mut().batch_execute("ROLLBACK").is_ok();
+                Err((rolled_back, e))
+            }
+            Err(payload) => {
+                self.inner.borrow_mut().batch_execute("ROLLBACK").ok();
+                std::panic::resume_unwind(payload);
+            }
+        }
+    }
+}
+
+impl super::Transaction for Client {
+    type Err = postgres::Error;
+
+    fn save_migration(&self, info: &ExecutedMigrationInfo) -> Result<(), Self::Err> {
+        let version_str = format!("{}", info.migration.version);
+        let sequence = info.sequence as i32;
+
+        self.inner.borrow_mut().execute(r"
+            insert into migration
+            (sequence, version, name, checksum)
+            values ($1, $2, $3, $4)
+        ", &[&sequence, &version_str, &info.migration.name, &info.migration.checksum])
+        .map(|_| ())
+    }
+
+    fn delete_migration(&self, version: &Version) -> Result<(), Self::Err> {
+        let version_str = format!("{}", version);
+
+        self.inner.borrow_mut().execute(r"
+            delete from migration
+            where version = $1
+        ", &[&version_str])
+        .map(|_| ())
+    }
+}
+
+fn is_missing_migration_table(err: &postgres::Error) -> bool {
+    is_missing_migration_table_message(&err.to_string())
+}
+
+fn is_missing_migration_table_message(message: &str) -> bool {
+    message.contains(r#"relation "migration" does not exist"#)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_missing_migration_table_error() {
+        assert!(is_missing_migration_table_message(
+            r#"db error: ERROR: relation "migration" does not exist"#));
+    }
+
+    #[test]
+    fn ignores_unrelated_errors() {
+        assert!(!is_missing_migration_table_message("db error: ERROR: syntax error at or near \"SELEC\""));
+    }
+}
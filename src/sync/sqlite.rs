@@ -1,4 +1,5 @@
-use super::super::{ExecutedMigrationInfo, MigrationInfo};
+use super::super::{ExecutedMigrationInfo, MigrationInfo, Version};
+use super::Migrations;
 
 impl super::Connection for rusqlite::Connection {
     type Err = rusqlite::Error;
@@ -10,6 +11,7 @@ impl super::Connection for rusqlite::Connection {
                 sequence integer not null primary key,
                 version text not null unique,
                 name text not null,
+                checksum blob not null,
                 applied_at text
             )
         ", rusqlite::NO_PARAMS)
@@ -17,13 +19,15 @@ impl super::Connection for rusqlite::Connection {
     }
 
     fn load_existing_migrations(&self) -> Result<Vec<ExecutedMigrationInfo>, Self::Err> {
-        self.prepare("SELECT sequence, version, name, applied_at FROM migration")?
+        self.prepare("SELECT sequence, version, name, checksum, applied_at FROM migration")?
             .query_map(rusqlite::NO_PARAMS, |row| {
+                let version_str: String = row.get(1);
                 ExecutedMigrationInfo {
                     sequence: row.get(0),
                     migration: MigrationInfo {
-                        version: row.get(1),
-                        name: row.get(2)
+                        version: version_str.parse().expect("invalid version stored in migration table"),
+                        name: row.get(2),
+                        checksum: row.get(3)
                     }
                 }
             })?
@@ -32,7 +36,21 @@ impl super::Connection for rusqlite::Connection {
 
     fn in_transaction<F>(&self, f: F) -> Result<(), (bool, Self::Err)>
         where F: FnOnce(&Self::Trans) -> Result<(), Self::Err> {
-        f(self).map_err(|e| (false, e))
+        self.execute("begin", rusqlite::NO_PARAMS).map_err(|e| (false, e))?;
+
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(self))) {
+            Ok(Ok(())) => self.execute("commit", rusqlite::NO_PARAMS)
+                .map(|_| ())
+                .map_err(|e| (false, e)),
+            Ok(Err(e)) => {
+                let rolled_back = self.execute("rollback", rusqlite::NO_PARAMS).is_ok();
+                Err((rolled_back, e))
+            }
+            Err(payload) => {
+                self.execute("rollback", rusqlite::NO_PARAMS).ok();
+                std::panic::resume_unwind(payload);
+            }
+        }
     }
 }
 
@@ -41,23 +59,150 @@ impl super::Transaction for rusqlite::Connection {
 
     fn save_migration(&self, info: &ExecutedMigrationInfo) -> Result<(), Self::Err> {
         let version_str = format!("{}", info.migration.version);
-        let params: [&rusqlite::types::ToSql;3] = [
+        let params: [&rusqlite::types::ToSql;4] = [
             &info.sequence,
             &version_str,
-            &info.migration.name
+            &info.migration.name,
+            &info.migration.checksum
         ];
 
         self.execute(r"
             insert into migration
-            (sequence, version, name)
-            values (?1, ?2, ?3)
+            (sequence, version, name, checksum)
+            values (?1, ?2, ?3, ?4)
+        ", &params)
+        .map(|_| ())
+    }
+
+    fn delete_migration(&self, version: &Version) -> Result<(), Self::Err> {
+        let version_str = format!("{}", version);
+        let params: [&rusqlite::types::ToSql;1] = [
+            &version_str
+        ];
+
+        self.execute(r"
+            delete from migration
+            where version = ?1
         ", &params)
         .map(|_| ())
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaVersion {
+    NoneSet,
+    Inside(u32)
+}
+
+pub fn current_version(conn: &rusqlite::Connection) -> Result<SchemaVersion, rusqlite::Error> {
+    let version: u32 = conn.query_row("PRAGMA user_version", rusqlite::NO_PARAMS, |row| row.get(0))?;
+    Ok(if version == 0 {
+        SchemaVersion::NoneSet
+    } else {
+        SchemaVersion::Inside(version)
+    })
+}
+
+fn set_version(conn: &rusqlite::Connection, version: u32) -> Result<(), rusqlite::Error> {
+    conn.execute(&format!("PRAGMA user_version = {}", version), rusqlite::NO_PARAMS)
+        .map(|_| ())
+}
+
+/// A `Connection` implementation that tracks applied migrations as a single
+/// `PRAGMA user_version` counter instead of a `migration` table, at the cost
+/// of not retaining each migration's name or checksum once applied.
+///
+/// `new` takes its migration list from the same `Migrations` instance passed
+/// to `migrate`/`rollback`, sorted by version, so the sequence it assigns to
+/// each migration always lines up with what `migrate`/`rollback` expect. This
+/// only holds if migrations are always added at a version higher than every
+/// migration already applied: inserting one at an earlier version than one
+/// that has already run will shift every later migration's position and
+/// desynchronize the counter from what's actually been applied.
+pub struct UserVersionConnection {
+    conn: rusqlite::Connection,
+    migrations: Vec<MigrationInfo>
+}
+
+impl UserVersionConnection {
+    pub fn new<M>(conn: rusqlite::Connection, migrations: &M) -> UserVersionConnection
+        where M: Migrations<C = UserVersionConnection> {
+        let mut migrations = migrations.all_migrations();
+        migrations.sort_unstable_by(|m1, m2| m1.version.cmp(&m2.version));
+        UserVersionConnection {
+            conn,
+            migrations
+        }
+    }
+
+    pub fn current_version(&self) -> Result<SchemaVersion, rusqlite::Error> {
+        current_version(&self.conn)
+    }
+}
+
+impl super::Connection for UserVersionConnection {
+    type Err = rusqlite::Error;
+    type Trans = Self;
+
+    fn ensure_migration_table(&self) -> Result<(), Self::Err> {
+        Ok(())
+    }
+
+    fn load_existing_migrations(&self) -> Result<Vec<ExecutedMigrationInfo>, Self::Err> {
+        let applied = match current_version(&self.conn)? {
+            SchemaVersion::NoneSet => 0,
+            SchemaVersion::Inside(version) => version
+        };
+
+        Ok(self.migrations.iter()
+            .take(applied as usize)
+            .enumerate()
+            .map(|(i, migration)| ExecutedMigrationInfo {
+                sequence: i as u32 + 1,
+                migration: migration.clone()
+            })
+            .collect())
+    }
+
+    fn in_transaction<F>(&self, f: F) -> Result<(), (bool, Self::Err)>
+        where F: FnOnce(&Self::Trans) -> Result<(), Self::Err> {
+        self.conn.execute("begin", rusqlite::NO_PARAMS).map_err(|e| (false, e))?;
+
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(self))) {
+            Ok(Ok(())) => self.conn.execute("commit", rusqlite::NO_PARAMS)
+                .map(|_| ())
+                .map_err(|e| (false, e)),
+            Ok(Err(e)) => {
+                let rolled_back = self.conn.execute("rollback", rusqlite::NO_PARAMS).is_ok();
+                Err((rolled_back, e))
+            }
+            Err(payload) => {
+                self.conn.execute("rollback", rusqlite::NO_PARAMS).ok();
+                std::panic::resume_unwind(payload);
+            }
+        }
+    }
+}
+
+impl super::Transaction for UserVersionConnection {
+    type Err = rusqlite::Error;
+
+    fn save_migration(&self, info: &ExecutedMigrationInfo) -> Result<(), Self::Err> {
+        set_version(&self.conn, info.sequence)
+    }
+
+    fn delete_migration(&self, _version: &Version) -> Result<(), Self::Err> {
+        let current = match current_version(&self.conn)? {
+            SchemaVersion::NoneSet => 0,
+            SchemaVersion::Inside(version) => version
+        };
+        set_version(&self.conn, current.saturating_sub(1))
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
     use super::super::*;
     use super::super::super::*;
 
@@ -76,15 +221,89 @@ mod tests {
             sequence: 1,
             migration: MigrationInfo {
                 version: "1.0.0".parse().unwrap(),
-                name: "test_migration".to_owned()
+                name: "test_migration".to_owned(),
+                checksum: vec!(1, 2, 3)
             }
         };
         connection.in_transaction(|t| t.save_migration(&migration)).unwrap();
 
         connection.in_transaction(|t| {
-            assert_eq!(migration.migration.version, 
+            assert_eq!(migration.migration.version,
                 t.load_existing_migrations().unwrap().get(0).unwrap().migration.version);
             Ok(())
         }).unwrap();
     }
+
+    #[test]
+    fn delete_migration() {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        connection.ensure_migration_table().unwrap();
+
+        let migration = ExecutedMigrationInfo {
+            sequence: 1,
+            migration: MigrationInfo {
+                version: "1.0.0".parse().unwrap(),
+                name: "test_migration".to_owned(),
+                checksum: vec!(1, 2, 3)
+            }
+        };
+        connection.in_transaction(|t| t.save_migration(&migration)).unwrap();
+        connection.in_transaction(|t| t.delete_migration(&migration.migration.version)).unwrap();
+
+        assert!(connection.load_existing_migrations().unwrap().is_empty());
+    }
+
+    #[test]
+    fn in_transaction_rolls_back_on_error() {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        connection.ensure_migration_table().unwrap();
+
+        let migration = ExecutedMigrationInfo {
+            sequence: 1,
+            migration: MigrationInfo {
+                version: "1.0.0".parse().unwrap(),
+                name: "test_migration".to_owned(),
+                checksum: vec!(1, 2, 3)
+            }
+        };
+
+        let result = connection.in_transaction(|t| {
+            t.save_migration(&migration)?;
+            Err(rusqlite::Error::ExecuteReturnedResults)
+        });
+
+        match result {
+            Err((rolled_back, rusqlite::Error::ExecuteReturnedResults)) => assert!(rolled_back),
+            o => panic!("Unexpected result {:?}", o)
+        }
+        assert!(connection.load_existing_migrations().unwrap().is_empty());
+    }
+
+    #[test]
+    fn user_version_starts_unset() {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        assert_eq!(SchemaVersion::NoneSet, current_version(&connection).unwrap());
+    }
+
+    #[test]
+    fn user_version_connection_tracks_applied_migrations() {
+        let raw_connection = rusqlite::Connection::open_in_memory().unwrap();
+        let mut migrations: MigrationsBuilder<UserVersionConnection> = MigrationsBuilder::new();
+        migrations.add_migration("1.0.0", "first", b"up 1.0.0", |_| Ok(())).unwrap();
+        migrations.add_migration("2.0.0", "second", b"up 2.0.0", |_| Ok(())).unwrap();
+        let connection = UserVersionConnection::new(raw_connection, &migrations);
+
+        connection.ensure_migration_table().unwrap();
+        assert!(connection.load_existing_migrations().unwrap().is_empty());
+
+        connection.in_transaction(|t| t.save_migration(&ExecutedMigrationInfo {
+            sequence: 1,
+            migration: MigrationInfo { version: "1.0.0".parse().unwrap(), name: "first".to_owned(), checksum: vec!(1) }
+        })).unwrap();
+
+        assert_eq!(SchemaVersion::Inside(1), connection.current_version().unwrap());
+        let existing = connection.load_existing_migrations().unwrap();
+        assert_eq!(1, existing.len());
+        assert_eq!("first", existing[0].migration.name);
+    }
 }
\ No newline at end of file